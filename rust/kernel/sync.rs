@@ -0,0 +1,258 @@
+// SPDX-License-Identifier: GPL-2.0
+
+//! Synchronisation primitives.
+//!
+//! This module contains the kernel APIs related to synchronisation that have been ported or
+//! ported for usage by Rust code in the kernel.
+
+use crate::{bindings, str::CStr, Opaque};
+use core::{
+    cell::UnsafeCell,
+    marker::{PhantomData, PhantomPinned},
+    pin::Pin,
+};
+
+mod spinlock;
+
+pub use spinlock::{SpinLock, SpinLockBackend};
+
+/// A kernel `lock_class_key`, used by `lockdep` to tell apart independent call sites that
+/// initialise the same lock type.
+///
+/// Wrapping it like this means callers never have to handle a raw `lock_class_key` pointer
+/// themselves: [`new_lock!`] declares one `static` key per call site, or a caller can declare its
+/// own `static` and hand a reference to [`NeedsLockClass::init`].
+#[repr(transparent)]
+pub struct LockClassKey(Opaque<bindings::lock_class_key>);
+
+// SAFETY: `LockClassKey` is opaque to Rust and only ever read by the C side through a pointer, so
+// sharing a `&LockClassKey` between threads is fine.
+unsafe impl Sync for LockClassKey {}
+
+impl LockClassKey {
+    /// Constructs a new, uninitialised lock class key.
+    pub const fn new() -> Self {
+        Self(Opaque::uninit())
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut bindings::lock_class_key {
+        self.0.get()
+    }
+}
+
+impl Default for LockClassKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Safely initialises a lock in place, generating a new lock class for it.
+///
+/// This is the macro [`crate::spinlock_init`] and friends are built on; prefer those where they
+/// exist rather than calling this directly.
+#[macro_export]
+macro_rules! new_lock {
+    ($obj:expr, $name:literal) => {{
+        static CLASS: $crate::sync::LockClassKey = $crate::sync::LockClassKey::new();
+        let obj = $obj;
+        let name = $crate::c_str!($name);
+        // SAFETY: `CLASS` has static lifetime, so it is valid for as long as the kernel needs it.
+        #[allow(unused_unsafe)]
+        unsafe {
+            $crate::sync::NeedsLockClass::init(obj, name, &CLASS)
+        };
+    }};
+}
+
+/// A trait for types that need a lock class during initialisation.
+///
+/// Implementers of this trait benefit from `lockdep` integration as long as they are initialised
+/// via one of the `*_init` macros, which assign a unique lock class to each call site.
+pub trait NeedsLockClass {
+    /// Initialises the type so that its members are ready for use.
+    ///
+    /// # Safety
+    ///
+    /// `key` must outlive the lock being initialised.
+    unsafe fn init(self: Pin<&mut Self>, name: &'static CStr, key: &'static LockClassKey);
+}
+
+/// The actual acquire/release procedure backing a [`Lock`].
+///
+/// Adding a new flavour of lock (a raw spinlock, a `Mutex`, ...) is just a matter of implementing
+/// this trait; [`Lock`] and [`Guard`] are generic over it and never need to change.
+///
+/// # Safety
+///
+/// Implementers must ensure that `lock` and `unlock` form a correct acquire/release pair for the
+/// underlying primitive, and that the `GuardState` passed to `unlock` is always the one `lock`
+/// (or, for [`IrqSaveBackend`], `lock_irqsave`) just produced.
+pub unsafe trait Backend {
+    /// The interrupt (or similar) state saved by an irqsave-style acquisition. `()` for backends
+    /// that have no such notion.
+    type State;
+
+    /// The state a [`Guard`] carries from acquisition to release.
+    type GuardState;
+
+    /// Acquires the lock, returning the state to store in the guard.
+    fn lock(&self) -> Self::GuardState;
+
+    /// Releases the lock.
+    ///
+    /// # Safety
+    ///
+    /// Must be called at most once, by the current owner of the lock, passing the `guard_state`
+    /// produced by the matching acquisition.
+    unsafe fn unlock(&self, guard_state: &Self::GuardState);
+
+    /// Reacquires the lock after [`Guard::do_unlocked`] released it, restoring `guard_state` in
+    /// place for the next `unlock`.
+    ///
+    /// The default implementation just takes the lock again with the plain acquisition
+    /// procedure; backends whose `GuardState` remembers *how* the lock was originally acquired
+    /// (e.g. [`SpinLockBackend`](spinlock::SpinLockBackend), which may have been taken via
+    /// irqsave) must override this to reacquire the same way.
+    fn relock(&self, guard_state: &mut Self::GuardState) {
+        *guard_state = self.lock();
+    }
+}
+
+/// A [`Backend`] that can also be acquired with interrupts disabled.
+///
+/// # Safety
+///
+/// `lock_irqsave` must disable interrupts as part of acquiring the lock and return the state
+/// needed to restore them in [`Backend::unlock`].
+pub unsafe trait IrqSaveBackend: Backend<GuardState = Option<<Self as Backend>::State>> {
+    /// Acquires the lock after disabling interrupts, returning the previous interrupt state.
+    fn lock_irqsave(&self) -> Self::State;
+}
+
+/// A generic mutual exclusion primitive, parameterised over the [`Backend`] that implements the
+/// actual acquire/release procedure.
+///
+/// [`SpinLock`] is the only lock flavour wired up so far, but none of this type (or [`Guard`]) is
+/// spinlock-specific: a `Mutex` or a raw spinlock can reuse both simply by implementing
+/// [`Backend`] (and [`IrqSaveBackend`], for flavours that support irqsave acquisition).
+pub struct Lock<T: ?Sized, B: Backend> {
+    pub(crate) backend: B,
+
+    /// Locks may be architecture-defined or embed self-referential C state, so they are
+    /// conservatively required to be pinned.
+    _pin: PhantomPinned,
+
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: `Lock` can be transferred across thread boundaries iff the data it protects can.
+unsafe impl<T: ?Sized + Send, B: Backend> Send for Lock<T, B> {}
+
+// SAFETY: `Lock` serialises the interior mutability it provides, so it is `Sync` as long as the
+// data it protects is `Send`.
+unsafe impl<T: ?Sized + Send, B: Backend> Sync for Lock<T, B> {}
+
+impl<T, B: Backend> Lock<T, B> {
+    /// Constructs a new lock with the given backend, protecting `t`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must call an appropriate init function (e.g. [`NeedsLockClass::init`]) before
+    /// using the lock.
+    pub const unsafe fn new(t: T, backend: B) -> Self {
+        Self {
+            backend,
+            data: UnsafeCell::new(t),
+            _pin: PhantomPinned,
+        }
+    }
+}
+
+impl<T: ?Sized, B: Backend> Lock<T, B> {
+    /// Locks the lock and gives the caller access to the data protected by it. Only one thread at
+    /// a time is allowed to access the protected data.
+    pub fn lock(&self) -> Guard<'_, T, B> {
+        let guard_state = self.backend.lock();
+        // SAFETY: The lock was just acquired.
+        unsafe { Guard::new(self, guard_state) }
+    }
+
+    /// Returns the data protected by the lock.
+    fn locked_data(&self) -> &UnsafeCell<T> {
+        &self.data
+    }
+}
+
+impl<T: ?Sized, B: IrqSaveBackend> Lock<T, B> {
+    /// Locks the lock and disables interrupts before doing so, giving the caller access to the
+    /// data protected by it. The saved interrupt state is restored when the returned [`Guard`] is
+    /// dropped. This can be used to prevent race conditions between interrupt handlers and normal
+    /// code.
+    pub fn irq_lock(&self) -> Guard<'_, T, B> {
+        let flags = self.backend.lock_irqsave();
+        // SAFETY: The lock was just acquired, with interrupts disabled.
+        unsafe { Guard::new(self, Some(flags)) }
+    }
+}
+
+/// A guard that allows access to the data protected by a lock without causing a deadlock or race
+/// condition.
+pub struct Guard<'a, T: ?Sized, B: Backend> {
+    pub(crate) lock: &'a Lock<T, B>,
+    guard_state: B::GuardState,
+    _not_send: PhantomData<*mut ()>,
+}
+
+// SAFETY: `Guard` is sync when the data protected by the lock is also sync.
+unsafe impl<T: ?Sized + Sync, B: Backend> Sync for Guard<'_, T, B> {}
+
+impl<T: ?Sized, B: Backend> core::ops::Deref for Guard<'_, T, B> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The caller owns the lock, so it is safe to deref the protected data.
+        unsafe { &*self.lock.locked_data().get() }
+    }
+}
+
+impl<T: ?Sized, B: Backend> core::ops::DerefMut for Guard<'_, T, B> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: The caller owns the lock, so it is safe to deref the protected data.
+        unsafe { &mut *self.lock.locked_data().get() }
+    }
+}
+
+impl<T: ?Sized, B: Backend> Drop for Guard<'_, T, B> {
+    fn drop(&mut self) {
+        // SAFETY: The caller owns the lock, so it is safe to give up ownership.
+        unsafe { self.lock.backend.unlock(&self.guard_state) };
+    }
+}
+
+impl<'a, T: ?Sized, B: Backend> Guard<'a, T, B> {
+    /// Constructs a new immediate guard for the given locked object, taken with `guard_state`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that it only calls this function when the lock is held, and that
+    /// `guard_state` is the one produced by the acquisition this guard represents.
+    pub unsafe fn new(lock: &'a Lock<T, B>, guard_state: B::GuardState) -> Self {
+        Self {
+            lock,
+            guard_state,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Temporarily unlocks the guard, runs `cb`, then reacquires the lock the same way it was
+    /// originally taken (plain or irqsave).
+    ///
+    /// This is the primitive a condition variable needs to drop the lock, sleep in `cb`, and come
+    /// back holding it correctly.
+    pub fn do_unlocked<F: FnOnce()>(&mut self, cb: F) {
+        // SAFETY: The guard owns the lock, so it is safe to give up ownership temporarily.
+        unsafe { self.lock.backend.unlock(&self.guard_state) };
+        cb();
+        self.lock.backend.relock(&mut self.guard_state);
+    }
+}
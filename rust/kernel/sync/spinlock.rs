@@ -6,10 +6,10 @@
 //!
 //! See <https://www.kernel.org/doc/Documentation/locking/spinlocks.txt>.
 
-use super::{Guard, Lock, NeedsLockClass};
+use super::{Backend, IrqSaveBackend, Lock, LockClassKey, NeedsLockClass};
 use crate::str::CStr;
 use crate::{bindings, c_types, Opaque};
-use core::{cell::UnsafeCell, marker::PhantomPinned, pin::Pin};
+use core::pin::Pin;
 
 extern "C" {
     #[allow(improper_ctypes)]
@@ -32,119 +32,140 @@ extern "C" {
 #[macro_export]
 macro_rules! spinlock_init {
     ($spinlock:expr, $name:literal) => {
-        $crate::init_with_lockdep!($spinlock, $name)
+        $crate::new_lock!($spinlock, $name)
     };
 }
 
-/// Exposes the kernel's [`spinlock_t`]. When multiple CPUs attempt to lock the same spinlock, only
-/// one at a time is allowed to progress, the others will block (spinning) until the spinlock is
-/// unlocked, at which point another CPU will be allowed to make progress.
+/// The [`Backend`] behind [`SpinLock`].
 ///
-/// A [`SpinLock`] must first be initialised with a call to [`SpinLock::init`] before it can be
-/// used. The [`spinlock_init`] macro is provided to automatically assign a new lock class to a
-/// spinlock instance.
-///
-/// [`SpinLock`] does not manage the interrupt state, so it can be used in only two cases: (a) when
-/// the caller knows that interrupts are disabled, or (b) when callers never use it in interrupt
-/// handlers (in which case it is ok for interrupts to be enabled).
-///
-/// [`spinlock_t`]: ../../../include/linux/spinlock.h
-pub struct SpinLock<T: ?Sized> {
+/// Holds the kernel's raw spinlock. A plain acquisition stores `None` in the guard; `irq_lock`
+/// (via [`IrqSaveBackend`]) stores the interrupt flags saved by `raw_spin_lock_irqsave`, and
+/// `unlock` branches on that to pick the right release path.
+pub struct SpinLockBackend {
     spin_lock: Opaque<bindings::spinlock>,
-
-    /// Spinlocks are architecture-defined. So we conservatively require them to be pinned in case
-    /// some architecture uses self-references now or in the future.
-    _pin: PhantomPinned,
-
-    data: UnsafeCell<T>,
 }
 
-// SAFETY: `SpinLock` can be transferred across thread boundaries iff the data it protects can.
-unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
-
-// SAFETY: `SpinLock` serialises the interior mutability it provides, so it is `Sync` as long as the
-// data it protects is `Send`.
-unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+// SAFETY: `SpinLockBackend` can be transferred across thread boundaries iff the data it protects
+// can, which is guaranteed by the bound on `Lock`'s own `Send`/`Sync` impls.
+unsafe impl Send for SpinLockBackend {}
+// SAFETY: see above.
+unsafe impl Sync for SpinLockBackend {}
 
-impl<T> SpinLock<T> {
-    /// Constructs a new spinlock.
+impl SpinLockBackend {
+    /// Constructs a new, uninitialised spinlock backend.
+    ///
+    /// Pass this to [`Lock::new`] to build a [`SpinLock`]; the resulting lock still needs a call
+    /// to [`NeedsLockClass::init`] (e.g. via [`spinlock_init`]) before use.
     ///
     /// # Safety
     ///
-    /// The caller must call [`SpinLock::init`] before using the spinlock.
-    pub const unsafe fn new(t: T) -> Self {
+    /// The caller must call [`NeedsLockClass::init`] on the resulting lock before using it.
+    pub const unsafe fn new() -> Self {
         Self {
             spin_lock: Opaque::uninit(),
-            data: UnsafeCell::new(t),
-            _pin: PhantomPinned,
         }
     }
 }
 
-impl<T: ?Sized> SpinLock<T> {
-    /// Locks the spinlock and gives the caller access to the data protected by it. Only one thread
-    /// at a time is allowed to access the protected data.
-    pub fn lock(&self) -> Guard<'_, Self> {
-        self.lock_noguard();
-        // SAFETY: The spinlock was just acquired.
-        unsafe { Guard::new(self) }
+// SAFETY: `lock` and `unlock` form a correct acquire/release pair, and `unlock` only ever receives
+// the `GuardState` that the matching `lock`/`lock_irqsave` call just produced.
+unsafe impl Backend for SpinLockBackend {
+    type State = u64;
+    type GuardState = Option<u64>;
+
+    fn lock(&self) -> Option<u64> {
+        // SAFETY: `spin_lock` points to valid memory.
+        unsafe { rust_helper_hard_spin_lock(self.spin_lock.get() as *mut bindings::raw_spinlock) };
+        None
     }
 
-    /// The `irq_lock` method is similar to `lock`, but it also disables interrupts before acquiring the lock. This can be used to prevent race conditions between interrupt handlers and normal code.
-    pub fn irq_lock(&self) -> Guard<'_, Self> {
-        self.lock_noguard();
+    unsafe fn unlock(&self, guard_state: &Option<u64>) {
+        if let Some(flags) = *guard_state {
+            // SAFETY: `spin_lock` points to valid memory and `flags` are the ones saved by the
+            // matching `raw_spin_lock_irqsave` call.
+            unsafe {
+                rust_helper_raw_spin_unlock_irqrestore(
+                    self.spin_lock.get() as *mut bindings::hard_spinlock_t,
+                    flags,
+                )
+            };
+        } else {
+            // SAFETY: `spin_lock` points to valid memory.
+            unsafe {
+                rust_helper_hard_spin_unlock(self.spin_lock.get() as *mut bindings::raw_spinlock)
+            };
+        }
+    }
 
-        // SAFETY: The spinlock was just acquired.
-        unsafe { Guard::new(self) }
+    fn relock(&self, guard_state: &mut Option<u64>) {
+        *guard_state = if guard_state.is_some() {
+            Some(self.lock_irqsave())
+        } else {
+            self.lock()
+        };
     }
+}
 
+// SAFETY: `lock_irqsave` disables interrupts as part of acquiring the lock and returns the flags
+// needed to restore them.
+unsafe impl IrqSaveBackend for SpinLockBackend {
+    fn lock_irqsave(&self) -> u64 {
+        // SAFETY: `spin_lock` points to valid memory.
+        unsafe {
+            rust_helper_raw_spin_lock_irqsave(self.spin_lock.get() as *mut bindings::hard_spinlock_t)
+        }
+    }
+}
+
+/// Exposes the kernel's [`spinlock_t`]. When multiple CPUs attempt to lock the same spinlock, only
+/// one at a time is allowed to progress, the others will block (spinning) until the spinlock is
+/// unlocked, at which point another CPU will be allowed to make progress.
+///
+/// A [`SpinLock`] must first be initialised with a call to [`NeedsLockClass::init`] (usually via
+/// the [`spinlock_init`] macro) before it can be used; construct one with
+/// `Lock::new(value, SpinLockBackend::new())`.
+///
+/// [`SpinLock`] does not manage the interrupt state on its own; [`SpinLock::lock`] can be used in
+/// only two cases: (a) when the caller knows that interrupts are disabled, or (b) when callers
+/// never use it in interrupt handlers (in which case it is ok for interrupts to be enabled). Use
+/// [`SpinLock::irq_lock`] when neither holds.
+///
+/// [`spinlock_t`]: ../../../include/linux/spinlock.h
+pub type SpinLock<T> = Lock<T, SpinLockBackend>;
+
+impl<T: ?Sized> Lock<T, SpinLockBackend> {
     /// The `irq_lock_noguard` method acquires the lock and disables interrupts, but does not return a `Guard`. Instead, it returns a `u64` that represents the previous interrupt state. This method is unsafe because it does not provide any guarantees about the lifetime of the lock.
     // FIXME: use this to enable the smp function
     pub fn irq_lock_noguard(&self) -> u64 {
+        // SAFETY: `spin_lock` points to valid memory.
         unsafe {
-            rust_helper_raw_spin_lock_irqsave(self.spin_lock.get() as *mut bindings::hard_spinlock_t)
+            rust_helper_raw_spin_lock_irqsave(
+                self.backend.spin_lock.get() as *mut bindings::hard_spinlock_t
+            )
         }
     }
 
     /// The `irq_unlock_noguard` method releases the lock and restores the interrupt state to the value given by `flags`. This method is unsafe because it does not check whether the lock is currently held by the caller.
     // FIXME: use this to enable the smp function
     pub fn irq_unlock_noguard(&self, flags: u64) {
+        // SAFETY: `spin_lock` points to valid memory.
         unsafe {
             rust_helper_raw_spin_unlock_irqrestore(
-                self.spin_lock.get() as *mut bindings::hard_spinlock_t,
+                self.backend.spin_lock.get() as *mut bindings::hard_spinlock_t,
                 flags,
             );
         }
     }
 }
 
-impl<T: ?Sized> NeedsLockClass for SpinLock<T> {
-    unsafe fn init(self: Pin<&mut Self>, name: &'static CStr, key: *mut bindings::lock_class_key) {
-        unsafe { rust_helper_spin_lock_init(self.spin_lock.get(), name.as_char_ptr(), key) };
-    }
-}
-
-impl<T: ?Sized> Lock for SpinLock<T> {
-    type Inner = T;
-
-    fn lock_noguard(&self) {
-        // SAFETY: `spin_lock` points to valid memory.
-        // unsafe { rust_helper_spin_lock(self.spin_lock.get()) };
-        unsafe { rust_helper_hard_spin_lock(self.spin_lock.get() as *mut bindings::raw_spinlock) };
-        // unsafe { rust_helper_hard_spin_lock((*self.spin_lock.get()).rlock()
-        // as *mut bindings::raw_spinlock) };
-    }
-
-    unsafe fn unlock(&self) {
-        // unsafe { rust_helper_spin_unlock(self.spin_lock.get()) };
+impl<T: ?Sized> NeedsLockClass for Lock<T, SpinLockBackend> {
+    unsafe fn init(self: Pin<&mut Self>, name: &'static CStr, key: &'static LockClassKey) {
         unsafe {
-            rust_helper_hard_spin_unlock(self.spin_lock.get() as *mut bindings::raw_spinlock)
+            rust_helper_spin_lock_init(
+                self.backend.spin_lock.get(),
+                name.as_char_ptr(),
+                key.as_ptr(),
+            )
         };
-        // unsafe { rust_helper_hard_spin_unlock((*self.spin_lock.get()).rlock()
-        // as *mut bindings::raw_spinlock) };
-    }
-
-    fn locked_data(&self) -> &UnsafeCell<T> {
-        &self.data
     }
 }